@@ -0,0 +1,29 @@
+use std::fmt;
+
+use tokio::sync::oneshot;
+
+use crate::channel::state::FlushResult;
+
+/// Commands sent to the background [`Worker`](super::state::Worker) to control its lifecycle.
+///
+/// `Flush` and `Close` carry a completion [`oneshot::Sender`] so a caller can await the point at
+/// which the worker has drained its buffer and received the server's response, rather than
+/// sleeping and hoping.
+pub enum Command {
+    /// Drain the current buffer, then report the outcome through the completion sender.
+    Flush(oneshot::Sender<FlushResult>),
+    /// Drain remaining telemetry and stop the worker, reporting the outcome through the sender.
+    Close(oneshot::Sender<FlushResult>),
+    /// Stop the worker immediately without draining.
+    Terminate,
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Flush(_) => write!(f, "flush"),
+            Command::Close(_) => write!(f, "close"),
+            Command::Terminate => write!(f, "terminate"),
+        }
+    }
+}
@@ -0,0 +1,206 @@
+mod command;
+mod retry;
+mod state;
+
+pub use state::{DeadLetter, FlushResult};
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crossbeam_channel::{unbounded, Sender};
+use log::error;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Permit};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::contracts::Envelope;
+use crate::transmitter::Transmitter;
+
+use self::command::Command;
+use self::state::{SpillQueue, Worker};
+
+/// Default number of retry attempts before telemetry is handed to the dead-letter callback.
+const DEFAULT_MAX_RETRIES: usize = 10;
+
+/// Default upper bound on the number of telemetry items in a single outbound batch.
+const DEFAULT_MAX_BATCH_SIZE: usize = 500;
+
+/// Default maximum number of bytes the on-disk spill queue may occupy.
+const DEFAULT_MAX_ON_DISK_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Default capacity of the bounded event channel.
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// In-memory telemetry channel. Buffers [`Envelope`]s on a bounded channel and hands them to a
+/// background [`Worker`] that transmits them on a fixed interval. Because the channel is bounded,
+/// a producer that outpaces the transmitter gets explicit backpressure via [`reserve`] or
+/// [`try_send`] instead of growing memory without bound.
+///
+/// [`reserve`]: InMemoryChannel::reserve
+/// [`try_send`]: InMemoryChannel::try_send
+pub struct InMemoryChannel {
+    event_sender: mpsc::Sender<Envelope>,
+    command_sender: Sender<Command>,
+    join: Option<JoinHandle<()>>,
+}
+
+impl InMemoryChannel {
+    /// Starts a new channel with default settings, spawning the background worker.
+    pub fn new(transmitter: Transmitter, interval: Duration) -> Self {
+        InMemoryChannelBuilder::new(transmitter, interval).build()
+    }
+
+    /// Enqueues a telemetry item for transmission, waiting for room when the buffer is full so a
+    /// fast producer is back-pressured rather than losing telemetry. Use
+    /// [`try_send`](Self::try_send) for the non-blocking, lossy path.
+    pub async fn send(&self, envelope: Envelope) {
+        if self.event_sender.send(envelope).await.is_err() {
+            error!("Telemetry worker has stopped; dropping item");
+        }
+    }
+
+    /// Attempts to enqueue a telemetry item without blocking, returning the item if the buffer is
+    /// full or the worker has stopped.
+    pub fn try_send(&self, envelope: Envelope) -> Result<(), TrySendError<Envelope>> {
+        self.event_sender.try_send(envelope)
+    }
+
+    /// Reserves capacity for one telemetry item, waiting for room when the buffer is full. The
+    /// returned [`Permit`] is used to enqueue the item once capacity is available, giving
+    /// producers explicit backpressure.
+    pub async fn reserve(&self) -> Option<Permit<'_, Envelope>> {
+        self.event_sender.reserve().await.ok()
+    }
+
+    /// Requests a flush and awaits the point at which the worker has drained the buffer and
+    /// received the server's response. Returns `None` if the worker has already stopped.
+    pub async fn flush(&self) -> Option<FlushResult> {
+        let (sender, receiver) = oneshot::channel();
+        if self.command_sender.send(Command::Flush(sender)).is_err() {
+            return None;
+        }
+        receiver.await.ok()
+    }
+
+    /// Drains the buffer, stops the worker, and awaits completion.
+    pub async fn close(&mut self) -> Option<FlushResult> {
+        let (sender, receiver) = oneshot::channel();
+        if self.command_sender.send(Command::Close(sender)).is_err() {
+            return None;
+        }
+        let result = receiver.await.ok();
+        if let Some(join) = self.join.take() {
+            let _ = join.await;
+        }
+        result
+    }
+
+    /// Stops the worker immediately without draining buffered telemetry.
+    pub fn terminate(&self) {
+        let _ = self.command_sender.send(Command::Terminate);
+    }
+}
+
+/// Builder for [`InMemoryChannel`], exposing the worker's tunable settings.
+pub struct InMemoryChannelBuilder {
+    transmitter: Transmitter,
+    interval: Duration,
+    capacity: usize,
+    storage_dir: Option<PathBuf>,
+    max_on_disk_bytes: u64,
+    max_retries: usize,
+    dead_letter: Option<DeadLetter>,
+    max_batch_size: usize,
+}
+
+impl InMemoryChannelBuilder {
+    /// Starts a builder with the given transmitter and flush interval and default settings.
+    pub fn new(transmitter: Transmitter, interval: Duration) -> Self {
+        Self {
+            transmitter,
+            interval,
+            capacity: DEFAULT_CAPACITY,
+            storage_dir: None,
+            max_on_disk_bytes: DEFAULT_MAX_ON_DISK_BYTES,
+            max_retries: DEFAULT_MAX_RETRIES,
+            dead_letter: None,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+        }
+    }
+
+    /// Sets the capacity of the bounded event channel, at which point producers see backpressure.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Caps the number of telemetry items in a single outbound batch so each POST stays within the
+    /// ingestion endpoint's payload limit; the overflow is re-queued for the next cycle.
+    pub fn max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Persists failed telemetry batches of 50 or more items under `dir` so buffered envelopes
+    /// survive a restart. Smaller failed batches are retried from memory only.
+    pub fn storage_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.storage_dir = Some(dir.into());
+        self
+    }
+
+    /// Caps the on-disk spill queue at `bytes`, dropping the oldest batches when exceeded. The most
+    /// recent batch is always kept, even if it alone exceeds the cap.
+    pub fn max_on_disk_bytes(mut self, bytes: u64) -> Self {
+        self.max_on_disk_bytes = bytes;
+        self
+    }
+
+    /// Sets the number of retry attempts before a batch is dead-lettered.
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Registers a callback invoked with envelopes that have exhausted their retry attempts, so
+    /// the application can log, persist, or re-enqueue them instead of losing them.
+    pub fn dead_letter<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(Vec<Envelope>) + Send + Sync + 'static,
+    {
+        self.dead_letter = Some(std::sync::Arc::new(callback));
+        self
+    }
+
+    /// Builds the channel and spawns the background worker.
+    pub fn build(self) -> InMemoryChannel {
+        let (event_sender, event_receiver) = mpsc::channel(self.capacity);
+        let (command_sender, command_receiver) = unbounded();
+
+        let storage = self.storage_dir.and_then(|dir| match SpillQueue::new(&dir, self.max_on_disk_bytes) {
+            Ok(storage) => Some(storage),
+            Err(err) => {
+                error!("Unable to open telemetry spill queue at {}: {}", dir.display(), err);
+                None
+            }
+        });
+
+        let worker = Worker::new(
+            self.transmitter,
+            event_receiver,
+            command_receiver,
+            self.interval,
+            storage,
+            self.max_retries,
+            self.dead_letter,
+            self.max_batch_size,
+        );
+        let join = tokio::spawn(async move { worker.run().await });
+
+        InMemoryChannel {
+            event_sender,
+            command_sender,
+            join: Some(join),
+        }
+    }
+}
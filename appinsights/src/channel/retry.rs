@@ -0,0 +1,37 @@
+use std::time::Duration;
+use std::vec::IntoIter;
+
+/// Schedule of back-off intervals used to space out retries of a failed telemetry batch.
+///
+/// A [`Retry`] yields one [`Duration`] per attempt and is exhausted once the configured ceiling is
+/// reached, at which point the batch is dead-lettered.
+pub struct Retry(IntoIter<Duration>);
+
+impl Retry {
+    /// A schedule with no retries; the batch is attempted exactly once.
+    pub fn once() -> Self {
+        Retry(Vec::new().into_iter())
+    }
+
+    /// An exponential back-off schedule capped at `max_retries` attempts, doubling from one second.
+    pub fn exponential(max_retries: usize) -> Self {
+        let timeouts = (0..max_retries)
+            .map(|attempt| Duration::from_secs(2u64.saturating_pow(attempt as u32)))
+            .collect::<Vec<_>>();
+        Retry(timeouts.into_iter())
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry::once()
+    }
+}
+
+impl Iterator for Retry {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
@@ -1,8 +1,16 @@
-use std::time::Duration;
+use std::cmp::max;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crossbeam_channel::{select, Receiver};
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
 use log::{debug, error, trace};
+use serde::{Deserialize, Serialize};
 use sm::{sm, Event};
+use tokio::sync::{mpsc, oneshot};
 
 use crate::contracts::Envelope;
 use crate::timeout;
@@ -17,8 +25,7 @@ sm! {
         InitialStates { Receiving }
 
         TimeoutExpired {
-            Receiving => Sending,
-            Waiting => Sending
+            Receiving => Sending
         }
 
         FlushRequested {
@@ -26,136 +33,394 @@ sm! {
         }
 
         CloseRequested {
-            Receiving => Sending,
-            Waiting => Stopped
+            Receiving => Sending
         }
 
         ItemsSentAndContinue {
             Sending => Receiving
         }
 
-        ItemsSentAndStop {
+        TerminateRequested {
+            Receiving => Stopped,
             Sending => Stopped
         }
+    }
+}
+
+/// Number of buffered envelopes a failed batch must reach before it is spilled to disk. Smaller
+/// failures stay in memory on the retry task; only a meaningful backlog is worth persisting.
+const SPILL_THRESHOLD: usize = 50;
+
+/// One persisted, retryable batch identified so it can be removed individually once delivered.
+#[derive(Serialize, Deserialize)]
+struct PersistedBatch {
+    id: u64,
+    items: Vec<Envelope>,
+}
+
+/// Append-only, newline-delimited JSON queue that persists failed telemetry batches to disk so
+/// buffered envelopes survive a process restart, giving at-least-once delivery across restarts.
+///
+/// Each line holds one [`PersistedBatch`]. New batches are appended with `O_APPEND`; the file is
+/// only rewritten to drop individual delivered batches or to evict the oldest batches once the
+/// configured byte cap is exceeded.
+pub(crate) struct SpillQueue {
+    path: PathBuf,
+    max_bytes: u64,
+    next_id: AtomicU64,
+    // Serializes file mutations across the receiving loop and the retry task.
+    lock: Mutex<()>,
+}
+
+impl SpillQueue {
+    pub(crate) fn new(dir: impl AsRef<Path>, max_bytes: u64) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let path = dir.join("retry-queue.jsonl");
+        // Continue ids past anything already persisted so recovered batches stay addressable.
+        let next_id = read_batches(&path)?.iter().map(|batch| batch.id + 1).max().unwrap_or(0);
+        Ok(Self {
+            path,
+            max_bytes,
+            next_id: AtomicU64::new(next_id),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Appends a failed batch and returns the id under which it was persisted.
+    fn append(&self, items: &[Envelope]) -> io::Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut line = serde_json::to_string(&PersistedBatch {
+            id,
+            items: items.to_vec(),
+        })
+        .map_err(io::Error::from)?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        drop(file);
+
+        self.enforce_cap()?;
+        Ok(id)
+    }
+
+    /// Reloads every persisted batch so it can be rescheduled for delivery on startup.
+    fn load(&self) -> Vec<PersistedBatch> {
+        let _guard = self.lock.lock().unwrap();
+        read_batches(&self.path).unwrap_or_else(|err| {
+            error!("Failed to read persisted telemetry batches: {}", err);
+            Vec::new()
+        })
+    }
+
+    /// Removes the given batches from disk once they have been delivered or dead-lettered.
+    fn remove(&self, ids: &[u64]) {
+        if ids.is_empty() {
+            return;
+        }
+        let _guard = self.lock.lock().unwrap();
+        if let Err(err) = self.rewrite(|batch| !ids.contains(&batch.id)) {
+            error!("Failed to prune delivered telemetry batches: {}", err);
+        }
+    }
 
-        RetryRequested {
-            Sending => Waiting
+    /// Drops the oldest batches until the backing file fits within the byte cap. The most recent
+    /// batch is always kept, even if it alone exceeds the cap. Caller holds the lock.
+    fn enforce_cap(&self) -> io::Result<()> {
+        let size = fs::metadata(&self.path).map(|meta| meta.len()).unwrap_or(0);
+        if size <= self.max_bytes {
+            return Ok(());
         }
 
-        RetryExhausted {
-            Waiting => Receiving
+        let mut batches = read_batches(&self.path)?;
+        let mut total = size;
+        let mut dropped = 0;
+        while total > self.max_bytes && batches.len() > 1 {
+            let batch = batches.remove(0);
+            total -= serialized_len(&batch);
+            dropped += 1;
+        }
+        if dropped > 0 {
+            debug!("Spill queue over capacity, dropping {} oldest persisted batch(es)", dropped);
         }
 
-        TerminateRequested {
-            Receiving => Stopped,
-            Sending => Stopped,
-            Waiting => Stopped
+        let mut file = File::create(&self.path)?;
+        for batch in &batches {
+            write_batch(&mut file, batch)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the file keeping only the batches for which `keep` returns true. Caller holds the
+    /// lock.
+    fn rewrite(&self, keep: impl Fn(&PersistedBatch) -> bool) -> io::Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        let retained: Vec<PersistedBatch> = read_batches(&self.path)?.into_iter().filter(|b| keep(b)).collect();
+        if retained.is_empty() {
+            return fs::remove_file(&self.path);
+        }
+        let mut file = File::create(&self.path)?;
+        for batch in &retained {
+            write_batch(&mut file, batch)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_batches(path: &Path) -> io::Result<Vec<PersistedBatch>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let mut batches = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<PersistedBatch>(&line) {
+            Ok(batch) => batches.push(batch),
+            Err(err) => error!("Failed to parse persisted telemetry batch: {}", err),
         }
     }
+    Ok(batches)
+}
+
+fn write_batch(file: &mut File, batch: &PersistedBatch) -> io::Result<()> {
+    let mut line = serde_json::to_string(batch).map_err(io::Error::from)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+}
+
+fn serialized_len(batch: &PersistedBatch) -> u64 {
+    serde_json::to_string(batch).map(|line| line.len() as u64 + 1).unwrap_or(0)
+}
+
+/// Message handed from the main receiving loop to the dedicated retry task.
+enum RetryMessage {
+    /// A batch the live send could not deliver; re-attempt it on the backoff schedule, honoring
+    /// `retry_after` as the minimum first-wait when the server asked us to throttle. `spill_ids`
+    /// identifies the batch's persisted entries (if any) so they can be pruned once delivered.
+    Batch {
+        items: Vec<Envelope>,
+        retry_after: Option<Duration>,
+        spill_ids: Vec<u64>,
+    },
+    /// Re-attempt the in-flight batch exactly once more, then stop (graceful `Close`).
+    Drain,
+    /// Stop immediately without any further attempts (hard `Terminate`).
+    Shutdown,
+}
+
+/// Shared deadline until which the ingestion endpoint asked us to back off. While it is in the
+/// future the main loop keeps buffering envelopes but does not start a new sending cycle.
+type BlockedUntil = Arc<Mutex<Option<Instant>>>;
+
+/// Callback invoked with envelopes that have exhausted their retry attempts, so applications can
+/// log, persist, or re-enqueue telemetry rather than losing it silently.
+pub type DeadLetter = Arc<dyn Fn(Vec<Envelope>) + Send + Sync>;
+
+/// Outcome of a `Flush`/`Close` delivered back to a caller awaiting completion, reporting how many
+/// telemetry items were sent, scheduled for retry, or dropped in the triggering sending cycle.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FlushResult {
+    pub sent: usize,
+    pub retried: usize,
+    pub dropped: usize,
 }
 
 pub struct Worker {
-    transmitter: Transmitter,
-    event_receiver: Receiver<Envelope>,
+    transmitter: Arc<Transmitter>,
+    // Bounded so a producer that outpaces the transmitter applies backpressure instead of growing
+    // memory without bound.
+    event_receiver: mpsc::Receiver<Envelope>,
     command_receiver: Receiver<Command>,
     interval: Duration,
+    // Handoff to the retry task that owns the failed-batch schedule so the receiving loop never
+    // blocks on backoff.
+    retry_sender: Sender<RetryMessage>,
+    retry_receiver: Receiver<RetryMessage>,
+    blocked_until: BlockedUntil,
+    // Optional disk-backed queue that persists failed batches so buffered telemetry survives a
+    // process restart.
+    storage: Option<Arc<SpillQueue>>,
+    // Maximum number of retry attempts before a batch is dead-lettered.
+    max_retries: usize,
+    // Optional callback invoked with envelopes that exhausted their retry attempts.
+    dead_letter: Option<DeadLetter>,
+    // Upper bound on the number of envelopes in a single outbound batch, keeping each POST within
+    // the ingestion endpoint's payload limit.
+    max_batch_size: usize,
+    // Batches rehydrated from `storage` on startup, rescheduled on the retry task at launch.
+    recovered: Vec<PersistedBatch>,
+    // Completion sender for an in-flight Flush/Close, fired once the triggered sending cycle
+    // resolves so callers can await true flush completion.
+    pending_flush: Option<oneshot::Sender<FlushResult>>,
 }
 
 impl Worker {
     pub fn new(
         transmitter: Transmitter,
-        event_receiver: Receiver<Envelope>,
+        event_receiver: mpsc::Receiver<Envelope>,
         command_receiver: Receiver<Command>,
         interval: Duration,
+        storage: Option<SpillQueue>,
+        max_retries: usize,
+        dead_letter: Option<DeadLetter>,
+        max_batch_size: usize,
     ) -> Self {
+        let storage = storage.map(Arc::new);
+
+        // Rehydrate any telemetry persisted before a previous restart; `run` hands it to the retry
+        // task at launch, while the receiving loop accepts fresh envelopes alongside it.
+        let recovered = storage.as_ref().map(|storage| storage.load()).unwrap_or_default();
+        if !recovered.is_empty() {
+            debug!("Recovered {} persisted telemetry batch(es) from disk", recovered.len());
+        }
+
+        let (retry_sender, retry_receiver) = unbounded();
+
         Self {
-            transmitter,
+            transmitter: Arc::new(transmitter),
             event_receiver,
             command_receiver,
             interval,
+            retry_sender,
+            retry_receiver,
+            blocked_until: Arc::new(Mutex::new(None)),
+            storage,
+            max_retries,
+            dead_letter,
+            max_batch_size,
+            recovered,
+            pending_flush: None,
         }
     }
 
-    pub async fn run(&self) {
-        let mut state = Machine::new(Receiving).as_enum();
+    pub async fn run(mut self) {
+        // Spin up the dedicated retry task. It owns the failed-batch schedule and re-attempts
+        // independently, so the main loop below stays responsive to commands and keeps draining
+        // freshly produced telemetry into new batches.
+        let retry_task = RetryWorker {
+            transmitter: Arc::clone(&self.transmitter),
+            retry_receiver: self.retry_receiver.clone(),
+            storage: self.storage.clone(),
+            blocked_until: Arc::clone(&self.blocked_until),
+            max_retries: self.max_retries,
+            dead_letter: self.dead_letter.clone(),
+        };
+        let retry_handle = tokio::spawn(async move { retry_task.run().await });
 
+        // Reschedule anything recovered from disk onto the retry task, carrying its existing spill
+        // ids so the persisted entries are pruned once delivered rather than re-persisted.
+        for batch in std::mem::take(&mut self.recovered) {
+            let _ = self.retry_sender.send(RetryMessage::Batch {
+                items: batch.items,
+                retry_after: None,
+                spill_ids: vec![batch.id],
+            });
+        }
+
+        let mut state = Machine::new(Receiving).as_enum();
         let mut items: Vec<Envelope> = Default::default();
-        let mut retry = Retry::default();
 
         loop {
             state = match state {
                 InitialReceiving(m) => self.handle_receiving(m, &mut items),
                 ReceivingByItemsSentAndContinue(m) => self.handle_receiving(m, &mut items),
-                ReceivingByRetryExhausted(m) => self.handle_receiving(m, &mut items),
-                SendingByTimeoutExpired(m) => self.handle_sending_with_retry(m, &mut items, &mut retry).await,
-                SendingByFlushRequested(m) => self.handle_sending_with_retry(m, &mut items, &mut retry).await,
-                SendingByCloseRequested(m) => self.handle_sending_once_and_terminate(m, &mut items, &mut retry).await,
-                WaitingByRetryRequested(m) => self.handle_waiting(m, &mut retry),
-                StoppedByItemsSentAndStop(_) => break,
-                StoppedByCloseRequested(_) => break,
+                SendingByTimeoutExpired(m) => self.handle_sending(m, &mut items).await,
+                SendingByFlushRequested(m) => self.handle_sending(m, &mut items).await,
+                SendingByCloseRequested(m) => self.handle_sending_once_and_terminate(m, &mut items).await,
                 StoppedByTerminateRequested(_) => break,
             }
         }
+
+        // Wait for the retry task to finish draining (on `Close`) or stop (on `Terminate`).
+        if let Err(err) = retry_handle.await {
+            error!("Retry task terminated unexpectedly: {}", err);
+        }
     }
 
-    fn handle_receiving<E: Event>(&self, m: Machine<Receiving, E>, items: &mut Vec<Envelope>) -> Variant {
+    fn handle_receiving<E: Event>(&mut self, m: Machine<Receiving, E>, items: &mut Vec<Envelope>) -> Variant {
         debug!("Receiving messages triggered by {:?}", m.trigger());
 
-        let timeout = timeout::after(self.interval);
+        // Clone the channel handles into locals so the `select!` borrow does not conflict with the
+        // mutable access we need to stash the flush-completion sender on `self`.
+        let command_receiver = self.command_receiver.clone();
+        let retry_sender = self.retry_sender.clone();
+
+        let mut timeout = timeout::after(self.interval);
         items.clear();
 
         loop {
             select! {
-                recv(self.command_receiver) -> command => {
+                recv(command_receiver) -> command => {
                     match command {
                         Ok(command) => {
                             trace!("Command received: {}", command);
                             match command {
-                                Command::Flush => return m.transition(FlushRequested).as_enum(),
-                                Command::Terminate => return m.transition(TerminateRequested).as_enum(),
-                                Command::Close => return m.transition(CloseRequested).as_enum(),
+                                Command::Flush(done) => {
+                                    self.pending_flush = Some(done);
+                                    return m.transition(FlushRequested).as_enum()
+                                },
+                                Command::Terminate => {
+                                    let _ = retry_sender.send(RetryMessage::Shutdown);
+                                    return m.transition(TerminateRequested).as_enum()
+                                },
+                                Command::Close(done) => {
+                                    self.pending_flush = Some(done);
+                                    return m.transition(CloseRequested).as_enum()
+                                },
                             }
                         },
                         Err(err) => {
                             error!("commands channel closed: {}", err);
+                            let _ = retry_sender.send(RetryMessage::Shutdown);
                             return m.transition(TerminateRequested).as_enum()
                         },
                     }
                 },
                 recv(timeout) -> _ => {
                     debug!("Timeout expired");
+                    // Do not hammer an endpoint that asked us to back off: keep buffering freshly
+                    // produced envelopes and re-arm the timeout until the throttle window passes.
+                    if ingestion_blocked(&self.blocked_until) {
+                        debug!("Ingestion is throttled, continue buffering telemetry items");
+                        self.drain_events(items);
+                        timeout = timeout::after(self.interval);
+                        continue;
+                    }
                     return m.transition(TimeoutExpired).as_enum()
                 },
             }
         }
     }
 
-    async fn handle_sending_with_retry<E: Event>(
-        &self,
-        m: Machine<Sending, E>,
-        items: &mut Vec<Envelope>,
-        retry: &mut Retry,
-    ) -> Variant {
-        *retry = Retry::exponential();
-        self.handle_sending(m, items).await
-    }
-
     async fn handle_sending_once_and_terminate<E: Event>(
-        &self,
+        &mut self,
         m: Machine<Sending, E>,
         items: &mut Vec<Envelope>,
-        retry: &mut Retry,
     ) -> Variant {
-        *retry = Retry::once();
-        let cloned = m.clone(); // clone here
+        // Keep a copy of the machine so we can drive the terminal transition after the borrow of
+        // `m` is consumed by the shared sending logic below.
+        let cloned = m.clone();
+        // Drains the entire buffered backlog (all payload-sized batches), handing anything
+        // undelivered to the retry task rather than leaving it for a later tick that never comes.
         self.handle_sending(m, items).await;
+        // Ask the retry task to re-attempt its in-flight batch once more before we stop, so a
+        // `Close` drains both the live batch and any retrying batch exactly once. Items that fail
+        // this final attempt are discarded.
+        let _ = self.retry_sender.send(RetryMessage::Drain);
         cloned.transition(TerminateRequested).as_enum()
     }
 
-    async fn handle_sending<E: Event>(&self, m: Machine<Sending, E>, items: &mut Vec<Envelope>) -> Variant {
-        // read items from a channel
-        let pending_items = self.event_receiver.try_iter();
-        items.extend(pending_items);
+    async fn handle_sending<E: Event>(&mut self, m: Machine<Sending, E>, items: &mut Vec<Envelope>) -> Variant {
+        // read items from the channel
+        self.drain_events(items);
 
         debug!(
             "Sending {} telemetry items triggered by {:?}",
@@ -163,73 +428,355 @@ impl Worker {
             m.trigger().unwrap()
         );
 
-        // submit items to the server if any
+        let mut result = FlushResult::default();
+
         if items.is_empty() {
             debug!("Nothing to send. Continue to wait");
-            m.transition(ItemsSentAndContinue).as_enum()
-        } else {
-            // attempt to send items
-            match self.transmitter.send(items).await {
+        }
+
+        // Drain the whole buffered backlog this cycle, one payload-sized batch at a time, so a
+        // caller awaiting Flush/Close is told the buffer is drained only once it actually is.
+        // Each POST stays within the ingestion endpoint's payload limit; anything left undelivered
+        // is handed to the retry task rather than deferred to a later tick (where a Close would
+        // drop it).
+        while !items.is_empty() {
+            let mut batch = if items.len() > self.max_batch_size {
+                let rest = items.split_off(self.max_batch_size);
+                std::mem::replace(items, rest)
+            } else {
+                std::mem::take(items)
+            };
+
+            // A single live attempt per batch; any failure is handed to the retry task so the
+            // worker never blocks on backoff here.
+            match self.transmitter.send(&mut batch).await {
                 Ok(Response::Success) => {
-                    items.clear();
-                    m.transition(ItemsSentAndContinue).as_enum()
+                    // A fresh live batch was never persisted, so there is nothing on disk to
+                    // clear here — doing so would erase unrelated, still-undelivered batches.
+                    result.sent += batch.len();
                 }
                 Ok(Response::Retry(retry_items)) => {
-                    *items = retry_items;
-                    m.transition(RetryRequested).as_enum()
+                    result.retried += retry_items.len();
+                    self.enqueue_retry(retry_items, None);
+                    // The endpoint is struggling; hand the rest of the backlog to the retry task
+                    // instead of continuing to POST into a failing endpoint.
+                    result.retried += items.len();
+                    let rest = std::mem::take(items);
+                    self.enqueue_retry(rest, None);
                 }
-                Ok(Response::Throttled(_retry_after, retry_items)) => {
-                    *items = retry_items;
-                    // TODO implement throttling instead
-                    m.transition(RetryRequested).as_enum()
+                Ok(Response::Throttled(retry_after, retry_items)) => {
+                    // Honor the server-specified Retry-After: block new sending cycles until the
+                    // window elapses and have the retry task wait at least this long.
+                    result.retried += retry_items.len();
+                    set_blocked_until(&self.blocked_until, retry_after);
+                    self.enqueue_retry(retry_items, Some(retry_after));
+                    result.retried += items.len();
+                    let rest = std::mem::take(items);
+                    self.enqueue_retry(rest, Some(retry_after));
                 }
                 Ok(Response::NoRetry) => {
-                    items.clear();
-                    m.transition(ItemsSentAndContinue).as_enum()
+                    result.dropped += batch.len();
                 }
                 Err(err) => {
                     debug!("Error occurred during sending telemetry items: {}", err);
-                    m.transition(RetryRequested).as_enum()
+                    result.retried += batch.len();
+                    self.enqueue_retry(batch, None);
+                    result.retried += items.len();
+                    let rest = std::mem::take(items);
+                    self.enqueue_retry(rest, None);
                 }
             }
         }
+
+        // Signal completion to a caller awaiting this Flush/Close, once the backlog is fully
+        // drained, reporting the totals across every batch sent this cycle.
+        if let Some(done) = self.pending_flush.take() {
+            let _ = done.send(result);
+        }
+
+        m.transition(ItemsSentAndContinue).as_enum()
     }
 
-    fn handle_waiting<E: Event>(&self, m: Machine<Waiting, E>, retry: &mut Retry) -> Variant {
-        if let Some(timeout) = retry.next() {
-            debug!(
-                "Waiting for retry timeout {:?} or stop command triggered by {:?}",
-                timeout,
-                m.state()
-            );
-            // sleep until next sending attempt
-            let timeout = timeout::after(timeout);
-
-            // wait for either retry timeout expired or stop command received
-            loop {
-                select! {
-                    recv(self.command_receiver) -> command => {
-                        match command {
-                            Ok(command) => match command {
-                                Command::Flush => continue,
-                                Command::Terminate => return m.transition(TerminateRequested).as_enum(),
-                                Command::Close => return m.transition(CloseRequested).as_enum(),
-                            },
-                            Err(err) => {
-                                error!("commands channel closed: {}", err);
-                                return m.transition(TerminateRequested).as_enum()
+    /// Persists a failed batch (when storage is configured and the backlog crosses the spill
+    /// threshold) and hands it off to the retry task, tagging it with its persisted id so the
+    /// retry task can prune exactly that batch once it is delivered.
+    fn enqueue_retry(&self, items: Vec<Envelope>, retry_after: Option<Duration>) {
+        if items.is_empty() {
+            return;
+        }
+
+        let mut spill_ids = Vec::new();
+        if let Some(storage) = &self.storage {
+            if items.len() >= SPILL_THRESHOLD {
+                match storage.append(&items) {
+                    Ok(id) => spill_ids.push(id),
+                    Err(err) => error!("Failed to persist telemetry items to spill queue: {}", err),
+                }
+            }
+        }
+
+        let message = RetryMessage::Batch {
+            items,
+            retry_after,
+            spill_ids,
+        };
+        if self.retry_sender.send(message).is_err() {
+            error!("Retry task is gone; dropping failed telemetry batch");
+        }
+    }
+
+    /// Drains all currently buffered envelopes from the bounded event channel into `items`.
+    fn drain_events(&mut self, items: &mut Vec<Envelope>) {
+        while let Ok(envelope) = self.event_receiver.try_recv() {
+            items.push(envelope);
+        }
+    }
+}
+
+/// Dedicated task that owns the failed-batch retry schedule. It re-attempts delivery on an
+/// exponential backoff independently of the main receiving loop, coalescing newly failed batches
+/// into the one in flight.
+struct RetryWorker {
+    transmitter: Arc<Transmitter>,
+    retry_receiver: Receiver<RetryMessage>,
+    storage: Option<Arc<SpillQueue>>,
+    blocked_until: BlockedUntil,
+    max_retries: usize,
+    dead_letter: Option<DeadLetter>,
+}
+
+impl RetryWorker {
+    async fn run(self) {
+        let mut pending: Vec<Envelope> = Vec::new();
+        let mut pending_ids: Vec<u64> = Vec::new();
+        let mut retry = Retry::exponential(self.max_retries);
+        let mut min_wait: Option<Duration> = None;
+
+        loop {
+            // Nothing in flight: block until the main loop hands us a failed batch or stops us.
+            if pending.is_empty() {
+                match self.retry_receiver.recv() {
+                    Ok(RetryMessage::Batch { items, retry_after, spill_ids }) => {
+                        pending = items;
+                        pending_ids = spill_ids;
+                        retry = Retry::exponential(self.max_retries);
+                        min_wait = retry_after;
+                    }
+                    Ok(RetryMessage::Drain) | Ok(RetryMessage::Shutdown) | Err(_) => return,
+                }
+                continue;
+            }
+
+            let timeout = match retry.next() {
+                Some(wait) => {
+                    // On the first wait honor whichever is longer: the exponential backoff interval
+                    // or the server-requested Retry-After duration.
+                    let wait = match min_wait.take() {
+                        Some(retry_after) => max(wait, retry_after),
+                        None => wait,
+                    };
+                    debug!("Retry task waiting {:?} before next attempt", wait);
+                    timeout::after(wait)
+                }
+                None => {
+                    // Retries exhausted: drop the persisted copies and hand the envelopes to the
+                    // dead-letter callback (if any) so the application can persist or re-enqueue
+                    // them instead of losing them.
+                    debug!("All retries exhausted, dead-lettering {} telemetry items", pending.len());
+                    if let Some(storage) = &self.storage {
+                        storage.remove(&pending_ids);
+                    }
+                    pending_ids.clear();
+                    let dropped = std::mem::take(&mut pending);
+                    if let Some(dead_letter) = &self.dead_letter {
+                        dead_letter(dropped);
+                    }
+                    continue;
+                }
+            };
+
+            select! {
+                recv(self.retry_receiver) -> message => {
+                    match message {
+                        Ok(RetryMessage::Batch { items, retry_after, spill_ids }) => {
+                            pending.extend(items);
+                            pending_ids.extend(spill_ids);
+                            if retry_after.is_some() {
+                                min_wait = retry_after;
                             }
                         }
-                    },
-                    recv(timeout) -> _ => {
-                        debug!("Retry timeout expired");
-                        return m.transition(TimeoutExpired).as_enum()
-                    },
+                        Ok(RetryMessage::Drain) => {
+                            self.attempt(&mut pending, &mut pending_ids).await;
+                            return;
+                        }
+                        Ok(RetryMessage::Shutdown) | Err(_) => return,
+                    }
+                },
+                recv(timeout) -> _ => {
+                    match self.attempt(&mut pending, &mut pending_ids).await {
+                        Outcome::Succeeded => retry = Retry::exponential(self.max_retries),
+                        // A repeated throttle carries a fresh server window; honor it on the next
+                        // wait so we never re-POST before the endpoint is ready again.
+                        Outcome::Retry { retry_after } => min_wait = retry_after.or(min_wait),
+                    }
+                },
+            }
+        }
+    }
+
+    /// Performs a single delivery attempt, updating on-disk state and the throttle window, and
+    /// reports whether the batch is settled: delivered, or rejected by the server as not retryable.
+    async fn attempt(&self, pending: &mut Vec<Envelope>, pending_ids: &mut Vec<u64>) -> Outcome {
+        if pending.is_empty() {
+            return Outcome::Succeeded;
+        }
+
+        match self.transmitter.send(pending).await {
+            Ok(Response::Success) | Ok(Response::NoRetry) => {
+                pending.clear();
+                // Settled: prune exactly this batch's persisted entries, leaving any other
+                // still-undelivered batches on disk untouched.
+                if let Some(storage) = &self.storage {
+                    storage.remove(pending_ids);
                 }
+                pending_ids.clear();
+                Outcome::Succeeded
             }
-        } else {
-            debug!("All retries exhausted by {:?}", m.state());
-            m.transition(RetryExhausted).as_enum()
+            Ok(Response::Retry(retry_items)) => {
+                *pending = retry_items;
+                Outcome::Retry { retry_after: None }
+            }
+            Ok(Response::Throttled(retry_after, retry_items)) => {
+                *pending = retry_items;
+                set_blocked_until(&self.blocked_until, retry_after);
+                Outcome::Retry {
+                    retry_after: Some(retry_after),
+                }
+            }
+            Err(err) => {
+                debug!("Error occurred while retrying telemetry items: {}", err);
+                Outcome::Retry { retry_after: None }
+            }
+        }
+    }
+}
+
+/// Result of a single retry attempt. A throttled response carries the server-requested window so
+/// the retry task can honor it on its next wait.
+enum Outcome {
+    Succeeded,
+    Retry { retry_after: Option<Duration> },
+}
+
+/// Returns whether the ingestion endpoint is still within a server-requested back-off window.
+/// Clears the deadline once it has elapsed so subsequent cycles resume normally.
+fn ingestion_blocked(blocked_until: &BlockedUntil) -> bool {
+    let mut guard = blocked_until.lock().unwrap();
+    match *guard {
+        Some(deadline) if Instant::now() < deadline => true,
+        Some(_) => {
+            *guard = None;
+            false
+        }
+        None => false,
+    }
+}
+
+/// Records that the ingestion endpoint asked us to back off for `retry_after`.
+fn set_blocked_until(blocked_until: &BlockedUntil, retry_after: Duration) {
+    *blocked_until.lock().unwrap() = Some(Instant::now() + retry_after);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    /// A throwaway directory under the system temp dir, removed when the test ends.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let unique = format!(
+                "appinsights-spill-{}-{}",
+                std::process::id(),
+                TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+            TempDir(std::env::temp_dir().join(unique))
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
         }
     }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn ids(batches: &[PersistedBatch]) -> Vec<u64> {
+        batches.iter().map(|batch| batch.id).collect()
+    }
+
+    #[test]
+    fn append_then_load_rehydrates_across_restart() {
+        let dir = TempDir::new();
+        let empty: Vec<Envelope> = Vec::new();
+
+        let queue = SpillQueue::new(dir.path(), 1024 * 1024).unwrap();
+        assert_eq!(queue.append(&empty).unwrap(), 0);
+        assert_eq!(queue.append(&empty).unwrap(), 1);
+        drop(queue);
+
+        // A fresh queue over the same directory (process restart) sees both persisted batches.
+        let recovered = SpillQueue::new(dir.path(), 1024 * 1024).unwrap();
+        assert_eq!(ids(&recovered.load()), vec![0, 1]);
+    }
+
+    #[test]
+    fn new_continues_ids_after_restart() {
+        let dir = TempDir::new();
+        let empty: Vec<Envelope> = Vec::new();
+
+        {
+            let queue = SpillQueue::new(dir.path(), 1024 * 1024).unwrap();
+            queue.append(&empty).unwrap();
+            queue.append(&empty).unwrap();
+        }
+
+        let queue = SpillQueue::new(dir.path(), 1024 * 1024).unwrap();
+        assert_eq!(queue.append(&empty).unwrap(), 2);
+    }
+
+    #[test]
+    fn remove_prunes_only_the_given_ids() {
+        let dir = TempDir::new();
+        let empty: Vec<Envelope> = Vec::new();
+
+        let queue = SpillQueue::new(dir.path(), 1024 * 1024).unwrap();
+        let keep_a = queue.append(&empty).unwrap();
+        let drop_it = queue.append(&empty).unwrap();
+        let keep_b = queue.append(&empty).unwrap();
+
+        queue.remove(&[drop_it]);
+
+        assert_eq!(ids(&queue.load()), vec![keep_a, keep_b]);
+    }
+
+    #[test]
+    fn enforce_cap_evicts_oldest_batches() {
+        let dir = TempDir::new();
+        let empty: Vec<Envelope> = Vec::new();
+
+        // Cap small enough that only the most recently appended batch fits.
+        let queue = SpillQueue::new(dir.path(), 24).unwrap();
+        queue.append(&empty).unwrap();
+        queue.append(&empty).unwrap();
+        queue.append(&empty).unwrap();
+
+        assert_eq!(ids(&queue.load()), vec![2]);
+    }
 }